@@ -1,11 +1,15 @@
+use std::collections::hash_map::DefaultHasher;
 use std::fmt::Formatter;
-use std::fs::File;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
 use std::io::{self, BufRead};
 use std::num::ParseIntError;
-use std::path::Path;
+use std::time::UNIX_EPOCH;
 use std::{env, error, fmt, num, result};
 
+use rayon::prelude::*;
 use regex::{self, Regex};
+use rusqlite::{self, Connection};
 
 #[derive(fmt::Debug)]
 struct Error {
@@ -46,21 +50,37 @@ impl From<regex::Error> for Error {
     }
 }
 
-fn read_lines<P: AsRef<Path>>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>> {
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::new(format!("cache error: {}", e))
+    }
+}
+
+fn read_lines(source: &str) -> io::Result<Box<dyn Iterator<Item = io::Result<String>>>> {
+    if source == "-" {
+        Ok(Box::new(io::BufReader::new(io::stdin()).lines()))
+    } else {
+        let file = File::open(source)?;
+        Ok(Box::new(io::BufReader::new(file).lines()))
+    }
 }
 
-struct Parser {
+trait Parser: Sync {
+    fn parse(&self, line: &str) -> Result<Record>;
+}
+
+struct RegexParser {
     re: Regex,
 }
 
-impl Parser {
-    fn new() -> Result<Parser> {
+impl RegexParser {
+    fn new() -> Result<RegexParser> {
         let re = Regex::new(r"^(?P<from>\d+)-(?P<to>\d+)\s(?P<letter>\w):\s(?P<password>.+)$")?;
-        Ok(Parser { re })
+        Ok(RegexParser { re })
     }
+}
 
+impl Parser for RegexParser {
     fn parse(&self, line: &str) -> Result<Record> {
         if let Some(caps) = self.re.captures(line) {
             let from = caps["from"].parse::<u64>()?;
@@ -77,6 +97,34 @@ impl Parser {
     }
 }
 
+struct SplitParser;
+
+impl SplitParser {
+    fn new() -> SplitParser {
+        SplitParser
+    }
+}
+
+impl Parser for SplitParser {
+    fn parse(&self, line: &str) -> Result<Record> {
+        let invalid = || Error::new("Invalid record".to_string());
+        let (range, rest) = line.split_once(' ').ok_or_else(invalid)?;
+        let (letter, password) = rest.split_once(": ").ok_or_else(invalid)?;
+        let (from, to) = range.split_once('-').ok_or_else(invalid)?;
+        let from = from.parse::<u64>()?;
+        let to = to.parse::<u64>()?;
+        let mut letters = letter.chars();
+        let letter = letters.next().ok_or_else(invalid)?;
+        if letters.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(Record {
+            policy: Policy { from, to, letter },
+            password: password.to_string(),
+        })
+    }
+}
+
 struct Policy {
     from: u64,
     to: u64,
@@ -90,54 +138,242 @@ struct Record {
 
 impl Record {
     fn validate(&self) -> (bool, bool) {
-        let chars = self.password.chars();
-        let count = chars.filter(|c| *c == self.policy.letter).count() as u64;
-        let old_policy = count >= self.policy.from && count <= self.policy.to;
+        let bytes = self.password.as_bytes();
+        let letter = self.policy.letter as u8;
+        let from = self.policy.from as usize;
+        let to = self.policy.to as usize;
+
+        let mut count = 0u64;
+        let mut at_from = false;
+        let mut at_to = false;
+        for (i, &b) in bytes.iter().enumerate() {
+            if b == letter {
+                count += 1;
+                let pos = i + 1;
+                if pos == from {
+                    at_from = true;
+                }
+                if pos == to {
+                    at_to = true;
+                }
+            }
+        }
 
-        let char_vec: Vec<char> = self.password.chars().collect();
-        let new_policy = (char_vec.len() as u64 >= self.policy.from
-            && char_vec[(self.policy.from - 1) as usize] == self.policy.letter)
-            != (char_vec.len() as u64 >= self.policy.to
-                && char_vec[(self.policy.to - 1) as usize] == self.policy.letter);
+        let old_policy = count >= self.policy.from && count <= self.policy.to;
+        let new_policy = at_from != at_to;
         (old_policy, new_policy)
     }
 }
 
+enum FilterTerm {
+    Old(bool),
+    New(bool),
+    Letter(char),
+    MinLen(usize),
+}
+
+fn parse_filter(expr: &str) -> Result<Vec<FilterTerm>> {
+    expr.split_whitespace()
+        .map(|token| match token {
+            "old" => Ok(FilterTerm::Old(true)),
+            "-old" => Ok(FilterTerm::Old(false)),
+            "new" => Ok(FilterTerm::New(true)),
+            "-new" => Ok(FilterTerm::New(false)),
+            _ => {
+                if let Some(letter) = token.strip_prefix("letter:") {
+                    let mut chars = letter.chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(c), None) => Ok(FilterTerm::Letter(c)),
+                        _ => Err(Error::new(format!("invalid filter term: {}", token))),
+                    }
+                } else if let Some(n) = token.strip_prefix("len>=") {
+                    Ok(FilterTerm::MinLen(n.parse::<usize>()?))
+                } else {
+                    Err(Error::new(format!("invalid filter term: {}", token)))
+                }
+            }
+        })
+        .collect()
+}
+
+impl Record {
+    fn matches(&self, terms: &[FilterTerm]) -> bool {
+        let (old, new) = self.validate();
+        terms.iter().all(|term| match term {
+            FilterTerm::Old(want) => old == *want,
+            FilterTerm::New(want) => new == *want,
+            FilterTerm::Letter(c) => self.policy.letter == *c,
+            FilterTerm::MinLen(n) => self.password.len() >= *n,
+        })
+    }
+}
+
+struct Cache {
+    conn: Connection,
+}
+
+impl Cache {
+    fn open(path: &str) -> Result<Cache> {
+        let conn = Connection::open(path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS results (
+                source_hash TEXT PRIMARY KEY,
+                old_valid INTEGER NOT NULL,
+                new_valid INTEGER NOT NULL,
+                mtime INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        Ok(Cache { conn })
+    }
+
+    fn get(&self, hash: &str) -> Result<Option<(u64, u64)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT old_valid, new_valid FROM results WHERE source_hash = ?1")?;
+        let mut rows = stmt.query([hash])?;
+        if let Some(row) = rows.next()? {
+            let old: i64 = row.get(0)?;
+            let new: i64 = row.get(1)?;
+            Ok(Some((old as u64, new as u64)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn put(&self, hash: &str, old: u64, new: u64, mtime: i64) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO results (source_hash, old_valid, new_valid, mtime) \
+             VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![hash, old as i64, new as i64, mtime],
+        )?;
+        Ok(())
+    }
+}
+
+fn source_hash(source: &str) -> Result<(String, i64)> {
+    let data = fs::read(source)?;
+    let mtime = fs::metadata(source)?
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs() as i64);
+
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    Ok((format!("{:016x}", hasher.finish()), mtime))
+}
+
+fn tally(parser: &dyn Parser, line: &str) -> (u64, u64) {
+    parser.parse(line).map_or((0u64, 0u64), |rec| {
+        let (old, new) = rec.validate();
+        (old as u64, new as u64)
+    })
+}
+
+fn count_source(parser: &dyn Parser, source: &str, jobs: usize) -> Result<(u64, u64)> {
+    let lines = read_lines(source)?;
+
+    if jobs > 1 {
+        let lines = lines.collect::<io::Result<Vec<String>>>()?;
+        let valid = lines
+            .par_iter()
+            .map(|line| tally(parser, line))
+            .reduce(|| (0u64, 0u64), |a, b| (a.0 + b.0, a.1 + b.1));
+        return Ok(valid);
+    }
+
+    let valid = lines
+        .map(|line_result| line_result.map_or((0u64, 0u64), |line| tally(parser, &line)))
+        .fold((0u64, 0u64), |acc, itm| (acc.0 + itm.0, acc.1 + itm.1));
+    Ok(valid)
+}
+
+fn filter_source(parser: &dyn Parser, source: &str, terms: &[FilterTerm]) -> Result<()> {
+    for line_result in read_lines(source)? {
+        let line = line_result?;
+        if let Ok(record) = parser.parse(&line) {
+            if record.matches(terms) {
+                println!("{}", record.password);
+            }
+        }
+    }
+    Ok(())
+}
+
 fn main() -> Result<()> {
     let args = env::args().collect::<Vec<String>>();
-    let parser = Parser::new()?;
-    if args.len() > 1 {
-        let lines = read_lines(&args[1])?;
-        let valid = lines
-            .map(|line_result| {
-                if let Ok(line) = line_result {
-                    parser.parse(&line)
-                } else {
-                    Err(Error::new(format!(
-                        "invalid data: {}",
-                        line_result.err().unwrap()
-                    )))
+
+    let mut backend = "regex";
+    let mut jobs = 1usize;
+    let mut cache_path: Option<&str> = None;
+    let mut filter: Option<&str> = None;
+    let mut sources: Vec<&str> = Vec::new();
+    for arg in &args[1..] {
+        if let Some(value) = arg.strip_prefix("--parser=") {
+            backend = value;
+        } else if let Some(value) = arg.strip_prefix("--jobs=") {
+            jobs = value.parse::<usize>()?;
+        } else if let Some(value) = arg.strip_prefix("--cache=") {
+            cache_path = Some(value);
+        } else if let Some(value) = arg.strip_prefix("--filter=") {
+            filter = Some(value);
+        } else {
+            sources.push(arg);
+        }
+    }
+
+    let parser: Box<dyn Parser> = match backend {
+        "regex" => Box::new(RegexParser::new()?),
+        "split" => Box::new(SplitParser::new()),
+        other => return Err(Error::new(format!("unknown parser backend: {}", other))),
+    };
+
+    if let Some(expr) = filter {
+        if sources.is_empty() {
+            return Err(Error::new("filename argument required".to_string()));
+        }
+        let terms = parse_filter(expr)?;
+        for source in &sources {
+            filter_source(parser.as_ref(), source, &terms)?;
+        }
+        return Ok(());
+    }
+
+    let cache = match cache_path {
+        Some(path) => Some(Cache::open(path)?),
+        None => None,
+    };
+
+    if !sources.is_empty() {
+        let mut totals = (0u64, 0u64);
+        for source in &sources {
+            let (old, new) = match &cache {
+                // stdin has no stable identity, so it is never cached.
+                Some(cache) if *source != "-" => {
+                    let (hash, mtime) = source_hash(source)?;
+                    if let Some(hit) = cache.get(&hash)? {
+                        hit
+                    } else {
+                        let counts = count_source(parser.as_ref(), source, jobs)?;
+                        cache.put(&hash, counts.0, counts.1, mtime)?;
+                        counts
+                    }
                 }
-            })
-            .map(|record_result| {
-                record_result.map_or((0u64, 0u64), |rec| {
-                    let (old, new) = rec.validate();
-                    (if old { 1 } else { 0 }, if new { 1 } else { 0 })
-                })
-            })
-            .fold((0u64, 0u64), |acc, itm| {
-                let (old_acc, new_acc) = acc;
-                let (old, new) = itm;
-                (old_acc + old, new_acc + new)
-            });
+                _ => count_source(parser.as_ref(), source, jobs)?,
+            };
+            println!("{}: old {}, new {}", source, old, new);
+            totals.0 += old;
+            totals.1 += new;
+        }
 
         println!(
             "The number of valid records by the old method is {}",
-            valid.0
+            totals.0
         );
         println!(
             "The number of valud records by the new method is {}",
-            valid.1
+            totals.1
         );
         Ok(())
     } else {
@@ -151,7 +387,7 @@ mod tests {
 
     #[test]
     fn parses_a_valid_db_record() -> result::Result<(), Error> {
-        let record = Parser::new()?.parse("3-11 z: zzzzzdzzzzlzz")?;
+        let record = RegexParser::new()?.parse("3-11 z: zzzzzdzzzzlzz")?;
         assert_eq!(3, record.policy.from);
         assert_eq!(11, record.policy.to);
         assert_eq!('z', record.policy.letter);
@@ -161,15 +397,57 @@ mod tests {
 
     #[test]
     fn validates_a_valid_password() -> result::Result<(), Error> {
-        let record = Parser::new()?.parse("1-3 a: abc")?;
-        assert!(record.validate());
+        let record = RegexParser::new()?.parse("1-3 a: abc")?;
+        let (old, _new) = record.validate();
+        assert!(old);
         Ok(())
     }
 
     #[test]
     fn does_not_validate_an_invalid_password() -> result::Result<(), Error> {
-        let record = Parser::new()?.parse("1-3 a: aaaa")?;
-        assert!(!record.validate());
+        let record = RegexParser::new()?.parse("1-3 a: aaaa")?;
+        let (old, new) = record.validate();
+        assert!(!old && !new);
+        Ok(())
+    }
+
+    #[test]
+    fn parallel_and_sequential_totals_agree() -> result::Result<(), Error> {
+        let parser = RegexParser::new()?;
+        let lines = [
+            "1-3 a: abcde",
+            "1-3 b: cdefg",
+            "2-9 c: ccccccccc",
+            "3-11 z: zzzzzdzzzzlzz",
+            "1-1 x: x",
+        ];
+
+        let sequential = lines
+            .iter()
+            .map(|line| tally(&parser, line))
+            .fold((0u64, 0u64), |acc, itm| (acc.0 + itm.0, acc.1 + itm.1));
+
+        let parallel = lines
+            .par_iter()
+            .map(|line| tally(&parser, line))
+            .reduce(|| (0u64, 0u64), |a, b| (a.0 + b.0, a.1 + b.1));
+
+        assert_eq!(sequential, parallel);
+        Ok(())
+    }
+
+    #[test]
+    fn both_backends_parse_identical_records() -> result::Result<(), Error> {
+        let regex = RegexParser::new()?;
+        let split = SplitParser::new();
+        for line in &["3-11 z: zzzzzdzzzzlzz", "1-3 a: abc", "2-9 c: ccccccccc"] {
+            let a = regex.parse(line)?;
+            let b = split.parse(line)?;
+            assert_eq!(a.policy.from, b.policy.from);
+            assert_eq!(a.policy.to, b.policy.to);
+            assert_eq!(a.policy.letter, b.policy.letter);
+            assert_eq!(a.password, b.password);
+        }
         Ok(())
     }
 }
\ No newline at end of file